@@ -1,12 +1,16 @@
 // This file would not be possible without Nekear
-use chrono::{DateTime, Datelike, Duration, Local, Timelike};
-use clap::Parser;
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDate, TimeZone, Timelike};
+use clap::{Parser, ValueEnum};
+use fs2::FileExt;
+use regex::Regex;
 use zip::write::FileOptions;
 use std::{
-    fs::{self, DirEntry},
+    collections::{HashMap, HashSet},
+    ffi::{OsStr, OsString},
+    io::{Read, Write},
     path::PathBuf,
     process::exit,
-    time::Instant, io::Write,
+    time::{Instant, SystemTime, UNIX_EPOCH},
 };
 
 #[derive(Parser, Debug)]
@@ -22,6 +26,316 @@ struct Args {
     /// Delete files older than <delete> days old
     #[clap(short, long)]
     delete: usize,
+
+    /// Amount of most recent daily archives to keep, one per calendar day
+    #[clap(long, default_value_t = 0)]
+    keep_daily: usize,
+
+    /// Amount of most recent weekly archives to keep, one per ISO week
+    #[clap(long, default_value_t = 0)]
+    keep_weekly: usize,
+
+    /// Amount of most recent monthly archives to keep, one per calendar month
+    #[clap(long, default_value_t = 0)]
+    keep_monthly: usize,
+
+    /// Amount of most recent yearly archives to keep, one per calendar year
+    #[clap(long, default_value_t = 0)]
+    keep_yearly: usize,
+
+    /// Roll over to a new archive volume once it would grow past this many bytes.
+    /// A value of 0 means archives are never split.
+    #[clap(long, default_value_t = 0)]
+    max_archive_size: u64,
+
+    /// Compression codec used for packed archives
+    #[clap(long, value_enum, default_value_t = Compression::Deflate)]
+    compression: Compression,
+
+    /// Compression level passed to the chosen codec. Valid ranges are
+    /// codec-specific: 0-9 for deflate/bzip2, -7-22 for zstd. Not supported
+    /// for `stored`.
+    #[clap(long)]
+    compression_level: Option<i32>,
+
+    /// Regex with named capture groups (`year`, `month`, `day`, optional
+    /// `hour`/`min`/`sec`) used to read a file's timestamp from its name
+    /// instead of its mtime. Falls back to mtime when the name doesn't match.
+    #[clap(long)]
+    date_pattern: Option<String>,
+
+    /// Skip files whose name doesn't match --date-pattern instead of
+    /// falling back to mtime
+    #[clap(long)]
+    require_match: bool,
+
+    /// Print what would be deleted/archived without touching the disk
+    #[clap(long)]
+    dry_run: bool,
+}
+
+/// One step of a run, either performed immediately or only recorded for a
+/// `--dry-run` report.
+enum Action {
+    Delete { name: String, size: u64 },
+    Archive { dest: String, members: Vec<(String, u64)> },
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum Compression {
+    Deflate,
+    Zstd,
+    Bzip2,
+    Stored,
+}
+
+impl Compression {
+    fn method(&self) -> zip::CompressionMethod {
+        return match self {
+            Compression::Deflate => zip::CompressionMethod::Deflated,
+            Compression::Zstd => zip::CompressionMethod::Zstd,
+            Compression::Bzip2 => zip::CompressionMethod::Bzip2,
+            Compression::Stored => zip::CompressionMethod::Stored,
+        };
+    }
+
+    // Valid compression level range for this codec, or `None` if the codec
+    // doesn't support levels at all.
+    fn level_range(&self) -> Option<std::ops::RangeInclusive<i32>> {
+        return match self {
+            Compression::Deflate => Some(0..=9),
+            Compression::Bzip2 => Some(0..=9),
+            Compression::Zstd => Some(-7..=22),
+            Compression::Stored => None,
+        };
+    }
+
+    fn validate_level(&self, level: Option<i32>) -> Result<Option<i32>, String> {
+        match (self.level_range(), level) {
+            (None, Some(_)) => Err(format!("{:?} compression does not support a level", self)),
+            (None, None) => Ok(None),
+            (Some(_), None) => Ok(None),
+            (Some(range), Some(level)) if range.contains(&level) => Ok(Some(level)),
+            (Some(range), Some(level)) => Err(format!(
+                "compression level {} is out of range for {:?} ({}-{})",
+                level, self, range.start(), range.end()
+            )),
+        }
+    }
+}
+
+/// Grandfather-father-son retention limits for already packed archives.
+///
+/// A `keep_*` value of `0` means that period keeps no archives. Pruning is
+/// skipped only when all four values are `0`.
+struct KeepOptions {
+    keep_daily: usize,
+    keep_weekly: usize,
+    keep_monthly: usize,
+    keep_yearly: usize,
+}
+
+impl KeepOptions {
+    fn is_noop(&self) -> bool {
+        self.keep_daily == 0 && self.keep_weekly == 0 && self.keep_monthly == 0 && self.keep_yearly == 0
+    }
+}
+
+impl From<&Args> for KeepOptions {
+    fn from(args: &Args) -> Self {
+        KeepOptions {
+            keep_daily: args.keep_daily,
+            keep_weekly: args.keep_weekly,
+            keep_monthly: args.keep_monthly,
+            keep_yearly: args.keep_yearly,
+        }
+    }
+}
+
+/// Settings that control how a day's files get packed into a zip archive.
+struct ArchiveOptions {
+    max_archive_size: u64,
+    compression: zip::CompressionMethod,
+    compression_level: Option<i32>,
+}
+
+impl ArchiveOptions {
+    fn from_args(args: &Args) -> Result<Self, String> {
+        let compression_level = args.compression.validate_level(args.compression_level)?;
+
+        return Ok(ArchiveOptions {
+            max_archive_size: args.max_archive_size,
+            compression: args.compression.method(),
+            compression_level,
+        });
+    }
+}
+
+/// Controls how a file's timestamp is determined.
+struct DateOptions {
+    pattern: Option<Regex>,
+    require_match: bool,
+}
+
+impl DateOptions {
+    fn from_args(args: &Args) -> Result<Self, String> {
+        let pattern = match &args.date_pattern {
+            Some(p) => Some(Regex::new(p).map_err(|err| format!("invalid --date-pattern: {}", err))?),
+            None => None,
+        };
+
+        return Ok(DateOptions {
+            pattern,
+            require_match: args.require_match,
+        });
+    }
+}
+
+/// Bundles everything a run needs beyond the directory being walked: the
+/// archive/delete cutoffs and the `*Options` groups, so `process_dir` and the
+/// functions it calls don't have to keep growing a bare parameter per
+/// feature.
+struct RunOptions {
+    archive_from: DateTime<Local>,
+    delete_from: DateTime<Local>,
+    keep: KeepOptions,
+    archive: ArchiveOptions,
+    date: DateOptions,
+    dry_run: bool,
+}
+
+impl RunOptions {
+    fn from_args(args: &Args) -> Result<Self, String> {
+        let local_time = chrono::offset::Local::now();
+        // Add -1 becuase of partition point algorithm finds the next index from the partition end.
+        // So, if we need to capture this day inclusively, we actually should search for the previous day
+        let archive_from = normalize_date(&(local_time - Duration::days(args.archive as i64 - 1)));
+        let delete_from = normalize_date(&(local_time - Duration::days(args.delete as i64)));
+
+        return Ok(RunOptions {
+            archive_from,
+            delete_from,
+            keep: KeepOptions::from(args),
+            archive: ArchiveOptions::from_args(args)?,
+            date: DateOptions::from_args(args)?,
+            dry_run: args.dry_run,
+        });
+    }
+}
+
+// Reads a named capture as a u32, falling back to `default` when the group
+// didn't participate in the match (e.g. optional hour/min/sec).
+fn capture_or(caps: &regex::Captures, name: &str, default: u32) -> Option<u32> {
+    match caps.name(name) {
+        Some(m) => m.as_str().parse().ok(),
+        None => Some(default),
+    }
+}
+
+// The name of the lock file we take an exclusive advisory lock on, so two
+// overlapping runs can't race on the same tree.
+const LOCK_FILE_NAME: &str = ".log_archiver.lock";
+
+// A file or directory entry read through a fd-relative `openat::Dir`, along
+// with just enough metadata to drive grouping/cutoff decisions without
+// re-resolving its full path.
+struct Entry {
+    name: OsString,
+    modified: SystemTime,
+    len: u64,
+}
+
+impl Entry {
+    fn file_name(&self) -> &OsStr {
+        return &self.name;
+    }
+}
+
+// Resolves the type of a listing entry, preferring the `d_type` the
+// `readdir()` call already gave us for free over an extra `fstatat`. Falls
+// back to a stat only when the filesystem didn't report a type.
+fn entry_type(dir: &openat::Dir, entry: &openat::Entry) -> Option<openat::SimpleType> {
+    if let Some(t) = entry.simple_type() {
+        return Some(t);
+    }
+
+    return dir.metadata(entry.file_name()).ok().map(|m| m.simple_type());
+}
+
+// Returns `None` instead of panicking when `name` can no longer be stat'd,
+// most commonly because it was deleted or renamed between the `list_dir()`
+// call and this stat - expected while the tree we're scanning is still being
+// written to.
+fn entry_metadata(dir: &openat::Dir, name: &OsStr) -> Option<Entry> {
+    let meta = dir.metadata(name).ok()?;
+    let stat = meta.stat();
+    let modified = UNIX_EPOCH + std::time::Duration::new(stat.st_mtime as u64, stat.st_mtime_nsec as u32);
+
+    return Some(Entry {
+        name: name.to_os_string(),
+        modified,
+        len: stat.st_size as u64,
+    });
+}
+
+// Lists regular files directly inside `dir`, skipping our own lock file.
+fn list_files(dir: &openat::Dir) -> Vec<Entry> {
+    return dir
+        .list_dir(".")
+        .unwrap()
+        .filter_map(|v| v.ok())
+        .filter(|v| v.file_name() != LOCK_FILE_NAME)
+        .filter(|v| entry_type(dir, v) == Some(openat::SimpleType::File))
+        .filter_map(|v| entry_metadata(dir, v.file_name()))
+        .collect();
+}
+
+// Lists the names of subdirectories directly inside `dir`.
+fn list_subdirs(dir: &openat::Dir) -> Vec<OsString> {
+    return dir
+        .list_dir(".")
+        .unwrap()
+        .filter_map(|v| v.ok())
+        .filter(|v| entry_type(dir, v) == Some(openat::SimpleType::Dir))
+        .map(|v| v.file_name().to_os_string())
+        .collect();
+}
+
+// Determines the timestamp to use for `entry`. When `opts.pattern` matches
+// the file name, the timestamp is parsed out of the name itself; otherwise
+// (or when there is no pattern) this falls back to the file's mtime.
+// A name that matches but yields no valid date is treated like a non-match.
+// Returns `None` only when `opts.require_match` is set and no date could be
+// parsed from the name.
+fn file_time(entry: &Entry, opts: &DateOptions) -> Option<DateTime<Local>> {
+    if let Some(re) = &opts.pattern {
+        let name = entry.file_name().to_string_lossy().into_owned();
+
+        if let Some(caps) = re.captures(&name) {
+            let parsed = (|| {
+                let year: i32 = caps.name("year")?.as_str().parse().ok()?;
+                let month: u32 = caps.name("month")?.as_str().parse().ok()?;
+                let day: u32 = caps.name("day")?.as_str().parse().ok()?;
+                let hour = capture_or(&caps, "hour", 0)?;
+                let min = capture_or(&caps, "min", 0)?;
+                let sec = capture_or(&caps, "sec", 0)?;
+
+                let naive = NaiveDate::from_ymd_opt(year, month, day)?.and_hms_opt(hour, min, sec)?;
+
+                return Local.from_local_datetime(&naive).single();
+            })();
+
+            if let Some(date) = parsed {
+                return Some(date);
+            }
+        }
+
+        if opts.require_match {
+            return None;
+        }
+    }
+
+    return Some(entry.modified.into());
 }
 
 fn main() {
@@ -38,34 +352,77 @@ fn main() {
         exit(1);
     }
 
-    let local_time = chrono::offset::Local::now();
-    // Add -1 becuase of partition point algorithm finds the next index from the partition end.
-    // So, if we need to capture this day inclusively, we actually should search for the previous day
-    let archive_from = local_time - Duration::days(args.archive as i64 - 1);
-    let archive_from = normalize_date(&archive_from);
+    let root_dir = openat::Dir::open(&args.directory).unwrap_or_else(|err| {
+        println!("Could not open {:#?}: {}", args.directory, err);
+        exit(1);
+    });
+
+    // Held for the lifetime of the run - two overlapping invocations over
+    // the same tree would otherwise race on archiving/deleting the same files.
+    // Skipped for --dry-run, which must not touch the disk at all.
+    let lock_file = if args.dry_run {
+        None
+    } else {
+        let lock_file = root_dir.write_file(LOCK_FILE_NAME, 0o644).unwrap_or_else(|err| {
+            println!("Could not create {}: {}", LOCK_FILE_NAME, err);
+            exit(1);
+        });
+
+        if lock_file.try_lock_exclusive().is_err() {
+            println!("Another instance is already processing {:#?}, exiting.", args.directory);
+            exit(0);
+        }
+
+        Some(lock_file)
+    };
+
+    let run = RunOptions::from_args(&args).unwrap_or_else(|err| {
+        println!("{}", err);
+        exit(1);
+    });
+
+    let mut plan: Vec<(DateTime<Local>, Action)> = vec![];
 
-    let delete_from = local_time - Duration::days(args.delete as i64);
-    let delete_from = normalize_date(&delete_from);
+    let processed = process_dir(&args.directory, &root_dir, &run, &mut plan);
 
-    let processed = process_dir(&args.directory, &archive_from, &delete_from);
+    if let Some(lock_file) = &lock_file {
+        FileExt::unlock(lock_file).ok();
+    }
+
+    if args.dry_run {
+        print_plan(&mut plan);
+    }
 
     println!("Done\n{:.2}s\n{} files", now.elapsed().as_secs_f32(), processed)
 }
 
-fn list_dir_files(path: &PathBuf) -> Vec<DirEntry> {
-    return fs::read_dir(path)
-        .unwrap()
-        .map(|v| v.unwrap())
-        .filter(|v| v.path().is_file())
-        .collect();
-}
+// Renders a dry-run plan in chronological order, followed by a summary of
+// the bytes that would be freed and the archives that would be created.
+fn print_plan(plan: &mut Vec<(DateTime<Local>, Action)>) {
+    plan.sort_by(|a, b| a.0.cmp(&b.0));
 
-fn list_subdirs(path: &PathBuf) -> Vec<DirEntry> {
-    return fs::read_dir(path)
-        .unwrap()
-        .map(|v| v.unwrap())
-        .filter(|v| v.path().is_dir())
-        .collect();
+    let mut freed_bytes: u64 = 0;
+    let mut archive_count = 0;
+
+    for (date, action) in plan.iter() {
+        match action {
+            Action::Delete { name, size } => {
+                println!("{} DELETE {} ({} bytes)", date.format("%Y-%m-%d"), name, size);
+                freed_bytes += size;
+            }
+            Action::Archive { dest, members } => {
+                println!("{} ARCHIVE -> {} ({} files)", date.format("%Y-%m-%d"), dest, members.len());
+                for (name, _) in members {
+                    println!("    {}", name);
+                }
+
+                archive_count += 1;
+                freed_bytes += members.iter().map(|(_, size)| size).sum::<u64>();
+            }
+        }
+    }
+
+    println!("Would free {} bytes, create {} archives", freed_bytes, archive_count);
 }
 
 // Sets date's time to midnight
@@ -83,40 +440,105 @@ fn is_same_day(a: &DateTime<Local>, b: &DateTime<Local>) -> bool {
     return a.year() == b.year() && a.month() == b.month() && a.day() == b.day();
 }
 
-fn pack_to_archive(files: &Vec<&DirEntry>, dir: &PathBuf, date: &DateTime<Local>) {
-    // Archives should have readable name that consists of directory name and date in format specified below
+// Builds the file name for archive volume number `volume` (1-indexed).
+// Volume 1 keeps the plain `dirname_dd-mm-YYYY.zip` name so single-volume
+// archives stay backwards compatible; later volumes get a `.partNN` suffix.
+fn volume_name(dir_name: &str, date: &DateTime<Local>, volume: usize) -> String {
     let human_readable = date.format("%d-%m-%Y");
-    let dest = dir.join(format!(
-        "{}_{}.zip",
-        dir.file_name().unwrap().to_str().unwrap(),
-        human_readable
-    ));
 
-    let file = fs::File::create(dest).unwrap();
-    let mut zip = zip::ZipWriter::new(file);
-    let options = FileOptions::default()
-        .compression_method(zip::CompressionMethod::DEFLATE);
+    if volume == 1 {
+        return format!("{}_{}.zip", dir_name, human_readable);
+    }
+
+    return format!("{}_{}.part{:02}.zip", dir_name, human_readable, volume);
+}
+
+// Splits `files` into the volumes they'd be packed into under
+// `max_archive_size`, without touching the disk. A single file larger than
+// the limit still gets a volume of its own rather than erroring.
+fn group_into_volumes<'a>(files: &[&'a Entry], max_archive_size: u64) -> Vec<Vec<&'a Entry>> {
+    let mut volumes: Vec<Vec<&Entry>> = vec![];
+    let mut current: Vec<&Entry> = vec![];
+    let mut current_size: u64 = 0;
+
+    for &f in files {
+        if max_archive_size > 0 && current_size > 0 && current_size + f.len > max_archive_size {
+            volumes.push(std::mem::take(&mut current));
+            current_size = 0;
+        }
+
+        current_size += f.len;
+        current.push(f);
+    }
+
+    if !current.is_empty() {
+        volumes.push(current);
+    }
 
-    for v in files {
-        // Pack file to archive
-        zip.start_file(v.file_name().to_string_lossy(), options).unwrap();
-        zip.write_all(&fs::read(v.path()).unwrap()).unwrap();
+    return volumes;
+}
 
-        // Remove the actual file from directory
-        fs::remove_file(v.path()).unwrap();
+fn pack_to_archive(
+    files: &Vec<&Entry>,
+    dir: &openat::Dir,
+    dir_name: &str,
+    date: &DateTime<Local>,
+    run: &RunOptions,
+    plan: &mut Vec<(DateTime<Local>, Action)>,
+) {
+    let opts = &run.archive;
+    let mut options = FileOptions::default().compression_method(opts.compression);
+    if let Some(level) = opts.compression_level {
+        options = options.compression_level(Some(level));
     }
 
-    zip.finish().unwrap();
+    let volumes = group_into_volumes(files, opts.max_archive_size);
+
+    for (i, members) in volumes.iter().enumerate() {
+        let dest = volume_name(dir_name, date, i + 1);
+
+        if run.dry_run {
+            plan.push((
+                date.clone(),
+                Action::Archive {
+                    dest,
+                    members: members.iter().map(|v| (v.file_name().to_string_lossy().into_owned(), v.len)).collect(),
+                },
+            ));
+            continue;
+        }
+
+        let file = dir.write_file(&dest, 0o644).unwrap();
+        let mut zip = zip::ZipWriter::new(file);
+
+        for v in members {
+            zip.start_file(v.file_name().to_string_lossy(), options).unwrap();
+            let mut contents = Vec::new();
+            dir.open_file(v.file_name()).unwrap().read_to_end(&mut contents).unwrap();
+            zip.write_all(&contents).unwrap();
+
+            // Remove the actual file from directory
+            dir.remove_file(v.file_name()).unwrap();
+        }
+
+        zip.finish().unwrap();
+    }
 }
 
-fn archive_files(files: &[DirEntry], parent_dir: &PathBuf) -> usize {
+fn archive_files(
+    files: &[Entry],
+    dir: &openat::Dir,
+    dir_name: &str,
+    run: &RunOptions,
+    plan: &mut Vec<(DateTime<Local>, Action)>,
+) -> usize {
     if files.len() < 1 {
         return 0;
     }
 
     // Start with date of the first file. We can do it, since files are sorted by date
-    let mut current_date: DateTime<Local> = files[0].metadata().unwrap().modified().unwrap().into();
-    let mut files_to_archive: Vec<&DirEntry> = vec![];
+    let mut current_date: DateTime<Local> = file_time(&files[0], &run.date).unwrap();
+    let mut files_to_archive: Vec<&Entry> = vec![];
     // Amount of files we've already packed
     let mut amount = 0;
 
@@ -126,7 +548,7 @@ fn archive_files(files: &[DirEntry], parent_dir: &PathBuf) -> usize {
             continue;
         }
 
-        let date: DateTime<Local> = f.metadata().unwrap().modified().unwrap().into();
+        let date: DateTime<Local> = file_time(f, &run.date).unwrap();
 
         // Continue adding until we get a different date
         if is_same_day(&date, &current_date) {
@@ -135,7 +557,7 @@ fn archive_files(files: &[DirEntry], parent_dir: &PathBuf) -> usize {
         }
 
         amount += files_to_archive.len();
-        pack_to_archive(&files_to_archive, &parent_dir, &current_date);
+        pack_to_archive(&files_to_archive, dir, dir_name, &current_date, run, plan);
 
 
         current_date = date.clone();
@@ -146,18 +568,169 @@ fn archive_files(files: &[DirEntry], parent_dir: &PathBuf) -> usize {
 
     // Archive last date
     amount += files_to_archive.len();
-    pack_to_archive(&files_to_archive, &parent_dir, &current_date);
+    pack_to_archive(&files_to_archive, dir, dir_name, &current_date, run, plan);
 
     return amount;
 }
 
-fn process_dir(dir: &PathBuf, archive_from: &DateTime<Local>, delete_from: &DateTime<Local>) -> usize {
-    let mut files = list_dir_files(dir);
+// Recovers the date an archive represents from its own file name (see
+// `volume_name`), rather than its mtime, which only reflects when the zip
+// happened to be written and collapses distinct log days onto the same
+// instant when a backlog is archived in a single run. Returns `None` for
+// names that don't match the `..._dd-mm-YYYY[.partNN].zip` convention.
+fn archive_date_from_name(name: &str) -> Option<DateTime<Local>> {
+    static RE: std::sync::OnceLock<Regex> = std::sync::OnceLock::new();
+    let re = RE.get_or_init(|| Regex::new(r"_(\d{2})-(\d{2})-(\d{4})(?:\.part\d+)?\.zip$").unwrap());
+    let caps = re.captures(name)?;
+
+    let day: u32 = caps.get(1)?.as_str().parse().ok()?;
+    let month: u32 = caps.get(2)?.as_str().parse().ok()?;
+    let year: i32 = caps.get(3)?.as_str().parse().ok()?;
+
+    let naive = NaiveDate::from_ymd_opt(year, month, day)?.and_hms_opt(0, 0, 0)?;
+    return Local.from_local_datetime(&naive).single();
+}
+
+// Period keys used to bucket an archive into the daily/weekly/monthly/yearly
+// grandfather-father-son generations.
+fn period_keys(date: &DateTime<Local>) -> (String, String, String, String) {
+    let daily = format!("{}-{}", date.year(), date.ordinal());
+
+    let iso = date.iso_week();
+    let weekly = format!("{}-{}", iso.year(), iso.week());
+
+    let monthly = format!("{}-{:02}", date.year(), date.month());
+    let yearly = format!("{}", date.year());
+
+    return (daily, weekly, monthly, yearly);
+}
+
+// Groups archives by the calendar day they represent (see
+// `archive_date_from_name`) and decides which whole days survive a
+// grandfather-father-son retention pass. A day split across multiple
+// `--max-archive-size` volumes is kept or deleted as one unit - otherwise
+// each volume would compete for the same period slot and siblings of a kept
+// day could be silently purged. Returns the indices into `archives` of the
+// ones that should be deleted.
+fn select_archives_to_delete(archives: &[(Entry, DateTime<Local>)], keep: &KeepOptions) -> Vec<usize> {
+    let mut by_day: HashMap<NaiveDate, Vec<usize>> = HashMap::new();
+    for (i, (_, date)) in archives.iter().enumerate() {
+        by_day.entry(date.date_naive()).or_default().push(i);
+    }
+
+    let mut days: Vec<(NaiveDate, Vec<usize>)> = by_day.into_iter().collect();
+    // Newest first, so the first day we see for a period key is the one
+    // that survives.
+    days.sort_by(|a, b| b.0.cmp(&a.0));
+
+    let mut seen_daily: HashSet<String> = HashSet::new();
+    let mut seen_weekly: HashSet<String> = HashSet::new();
+    let mut seen_monthly: HashSet<String> = HashSet::new();
+    let mut seen_yearly: HashSet<String> = HashSet::new();
+
+    let mut daily_count = 0;
+    let mut weekly_count = 0;
+    let mut monthly_count = 0;
+    let mut yearly_count = 0;
+
+    let mut to_delete = vec![];
+
+    for (_, indices) in days {
+        let date = archives[indices[0]].1;
+        let (daily_key, weekly_key, monthly_key, yearly_key) = period_keys(&date);
+
+        let mut claimed = false;
+
+        if keep.keep_daily > 0 && daily_count < keep.keep_daily && !seen_daily.contains(&daily_key) {
+            seen_daily.insert(daily_key);
+            daily_count += 1;
+            claimed = true;
+        }
+
+        if keep.keep_weekly > 0 && weekly_count < keep.keep_weekly && !seen_weekly.contains(&weekly_key) {
+            seen_weekly.insert(weekly_key);
+            weekly_count += 1;
+            claimed = true;
+        }
+
+        if keep.keep_monthly > 0 && monthly_count < keep.keep_monthly && !seen_monthly.contains(&monthly_key) {
+            seen_monthly.insert(monthly_key);
+            monthly_count += 1;
+            claimed = true;
+        }
+
+        if keep.keep_yearly > 0 && yearly_count < keep.keep_yearly && !seen_yearly.contains(&yearly_key) {
+            seen_yearly.insert(yearly_key);
+            yearly_count += 1;
+            claimed = true;
+        }
+
+        if !claimed {
+            to_delete.extend(indices);
+        }
+    }
+
+    return to_delete;
+}
+
+// Applies a grandfather-father-son retention policy to the archives already
+// present in `dir`, deleting everything that isn't claimed by one of the
+// `keep_*` generations. Returns the amount of deleted archives.
+fn apply_retention(dir: &openat::Dir, run: &RunOptions, plan: &mut Vec<(DateTime<Local>, Action)>) -> usize {
+    let keep = &run.keep;
+
+    if keep.is_noop() {
+        return 0;
+    }
+
+    let archives: Vec<(Entry, DateTime<Local>)> = list_files(dir)
+        .into_iter()
+        .filter(|v| v.file_name().to_string_lossy().ends_with(".zip"))
+        .map(|v| {
+            let date = archive_date_from_name(&v.file_name().to_string_lossy()).unwrap_or_else(|| v.modified.into());
+            return (v, date);
+        })
+        .collect();
+
+    let to_delete = select_archives_to_delete(&archives, keep);
+
+    for &i in &to_delete {
+        let (archive, date) = &archives[i];
+
+        if run.dry_run {
+            plan.push((
+                *date,
+                Action::Delete {
+                    name: archive.file_name().to_string_lossy().into_owned(),
+                    size: archive.len,
+                },
+            ));
+        } else {
+            dir.remove_file(archive.file_name()).unwrap();
+        }
+    }
+
+    return to_delete.len();
+}
+
+fn process_dir(
+    dir_path: &PathBuf,
+    dir: &openat::Dir,
+    run: &RunOptions,
+    plan: &mut Vec<(DateTime<Local>, Action)>,
+) -> usize {
+    // Files whose name doesn't match --date-pattern are dropped entirely
+    // when --require-match is set; otherwise file_time() already falls
+    // back to mtime for them.
+    let mut files: Vec<Entry> = list_files(dir)
+        .into_iter()
+        .filter(|f| file_time(f, &run.date).is_some())
+        .collect();
 
     // Sort from oldest to newest
     files.sort_by(|a, b| {
-        let a_upd = a.metadata().unwrap().modified().unwrap();
-        let b_upd = b.metadata().unwrap().modified().unwrap();
+        let a_upd = file_time(a, &run.date).unwrap();
+        let b_upd = file_time(b, &run.date).unwrap();
 
         return a_upd.cmp(&b_upd);
     });
@@ -168,8 +741,8 @@ fn process_dir(dir: &PathBuf, archive_from: &DateTime<Local>, delete_from: &Date
     if len > 0 {
         // Find index when too old files end
         let start = files.partition_point(|probe| {
-            let time: DateTime<Local> = probe.metadata().unwrap().modified().unwrap().into();
-            return time < *delete_from;
+            let time = file_time(probe, &run.date).unwrap();
+            return time < run.delete_from;
         });
 
         // checking for bounds to not overflow the bound
@@ -178,21 +751,99 @@ fn process_dir(dir: &PathBuf, archive_from: &DateTime<Local>, delete_from: &Date
             // Everything after start should not be touched as too new
             let end = start
                 + files[start..].partition_point(|probe| {
-                    let time: DateTime<Local> = probe.metadata().unwrap().modified().unwrap().into();
-                    return time < *archive_from;
+                    let time = file_time(probe, &run.date).unwrap();
+                    return time < run.archive_from;
                 });
 
             for deleted in &files[0..start] {
-                fs::remove_file(deleted.path()).unwrap();
+                if run.dry_run {
+                    plan.push((
+                        file_time(deleted, &run.date).unwrap(),
+                        Action::Delete {
+                            name: deleted.file_name().to_string_lossy().into_owned(),
+                            size: deleted.len,
+                        },
+                    ));
+                } else {
+                    dir.remove_file(deleted.file_name()).unwrap();
+                }
             }
 
-            processed = archive_files(&files[start..end], &dir);
+            let dir_name = dir_path.file_name().unwrap().to_str().unwrap();
+            processed = archive_files(&files[start..end], dir, dir_name, run, plan);
         }
     }
 
-    for sub in list_subdirs(&dir) {
-        processed += process_dir(&sub.path(), archive_from, delete_from);
+    processed += apply_retention(dir, run, plan);
+
+    for sub_name in list_subdirs(dir) {
+        let sub_path = dir_path.join(&sub_name);
+        let sub_dir = dir.sub_dir(sub_name.as_os_str()).unwrap();
+        processed += process_dir(&sub_path, &sub_dir, run, plan);
     }
 
     return processed;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Reproduces the data-loss bug from backfilling a log directory in one
+    // run: several archives share the same mtime (the moment they were all
+    // written), but represent different log days embedded in their file
+    // names. Bucketing by mtime collapses them into a single daily/weekly/
+    // monthly/yearly key; bucketing by the embedded date keeps them distinct.
+    #[test]
+    fn archive_date_from_name_recovers_distinct_dates_despite_shared_mtime() {
+        let jan = archive_date_from_name("logs_15-01-2024.zip").unwrap();
+        let mar = archive_date_from_name("logs_15-03-2024.zip").unwrap();
+        let part = archive_date_from_name("logs_15-03-2024.part02.zip").unwrap();
+
+        assert_ne!(period_keys(&jan).2, period_keys(&mar).2, "different months should bucket into different monthly keys");
+        assert_eq!(period_keys(&mar), period_keys(&part), "volumes of the same day should share all period keys");
+    }
+
+    #[test]
+    fn archive_date_from_name_rejects_unrecognized_names() {
+        assert!(archive_date_from_name("logs.zip").is_none());
+        assert!(archive_date_from_name("not-an-archive.txt").is_none());
+    }
+
+    fn entry(name: &str) -> Entry {
+        return Entry {
+            name: OsString::from(name),
+            modified: SystemTime::UNIX_EPOCH,
+            len: 1,
+        };
+    }
+
+    // A day's archive split into multiple `--max-archive-size` volumes must
+    // survive or be deleted together - one volume claiming a period slot
+    // shouldn't leave its siblings treated as uncovered and purged.
+    #[test]
+    fn select_archives_to_delete_keeps_every_volume_of_a_kept_day() {
+        let kept_volume_1 = entry("logs_15-03-2024.zip");
+        let kept_volume_2 = entry("logs_15-03-2024.part02.zip");
+        let older_day = entry("logs_01-01-2024.zip");
+
+        let archives: Vec<(Entry, DateTime<Local>)> = vec![kept_volume_1, kept_volume_2, older_day]
+            .into_iter()
+            .map(|e| {
+                let date = archive_date_from_name(&e.file_name().to_string_lossy()).unwrap();
+                return (e, date);
+            })
+            .collect();
+
+        let keep = KeepOptions {
+            keep_daily: 1,
+            keep_weekly: 0,
+            keep_monthly: 0,
+            keep_yearly: 0,
+        };
+
+        let to_delete = select_archives_to_delete(&archives, &keep);
+
+        assert_eq!(to_delete, vec![2], "only the older day should be deleted, both volumes of the kept day should survive");
+    }
+}